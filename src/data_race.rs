@@ -0,0 +1,92 @@
+use std::cmp::max;
+
+use rustc_index::vec::{Idx, IndexVec};
+
+use crate::*;
+
+/// A vector clock, recording for each thread the timestamp of the most
+/// recent event of that thread that is known to have happened-before the
+/// point in the execution that this clock represents. A thread that has
+/// never been observed implicitly has a timestamp of 0.
+///
+/// Clocks grow lazily as new threads are spawned, just like the `IndexVec`s
+/// that back the synchronization primitives in `sync.rs`.
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct VClock(IndexVec<ThreadId, u32>);
+
+impl VClock {
+    /// Update `self` to the element-wise maximum of `self` and `other`, i.e.
+    /// join the two clocks in the happens-before lattice. This is how a
+    /// thread acquires the happens-before knowledge released by another.
+    pub(super) fn join(&mut self, other: &VClock) {
+        if other.0.len() > self.0.len() {
+            self.0.resize(other.0.len(), 0);
+        }
+        for (this, other) in self.0.iter_mut().zip(other.0.iter()) {
+            *this = max(*this, *other);
+        }
+    }
+
+    /// Record a new event for `thread`, bumping its timestamp in this clock.
+    pub(super) fn increment(&mut self, thread: ThreadId) {
+        let index = thread.index();
+        if index >= self.0.len() {
+            self.0.resize(index + 1, 0);
+        }
+        self.0[thread] += 1;
+    }
+}
+
+/// Per-thread vector clocks, shared by every synchronization primitive that
+/// needs to record a happens-before edge.
+#[derive(Default, Debug)]
+pub(super) struct GlobalState {
+    /// The current vector clock of each live thread.
+    clocks: IndexVec<ThreadId, VClock>,
+}
+
+impl GlobalState {
+    /// The current vector clock of `thread`, or the empty clock if `thread`
+    /// has not been observed yet.
+    fn clock(&self, thread: ThreadId) -> VClock {
+        if thread.index() < self.clocks.len() { self.clocks[thread].clone() } else { VClock::default() }
+    }
+
+    /// A mutable handle to the vector clock of `thread`, growing the
+    /// backing storage if this is the first time `thread` is observed.
+    fn clock_mut(&mut self, thread: ThreadId) -> &mut VClock {
+        let index = thread.index();
+        if index >= self.clocks.len() {
+            self.clocks.resize(index + 1, VClock::default());
+        }
+        &mut self.clocks[thread]
+    }
+}
+
+// Public interface for synchronizing on happens-before edges. The lock,
+// condvar and futex implementations in `sync.rs` perform all of their
+// release and acquire steps through it, and other subsystems (e.g. address
+// reuse in the allocator) can use it as well.
+impl<'mir, 'tcx: 'mir> EvalContextExt<'mir, 'tcx> for crate::MiriEvalContext<'mir, 'tcx> {}
+pub trait EvalContextExt<'mir, 'tcx: 'mir>: crate::MiriEvalContextExt<'mir, 'tcx> {
+    /// Take a snapshot of the active thread's current vector clock, to be
+    /// stashed alongside whatever is being released (a lock, a condvar
+    /// wakeup, a freed allocation, ...) so that a future acquire can
+    /// synchronize with it.
+    fn release_clock(&mut self) -> VClock {
+        let this = self.eval_context_mut();
+        let thread = this.active_thread();
+        this.machine.data_race.clock(thread)
+    }
+
+    /// Join a previously-released clock into `thread`'s current vector
+    /// clock and record a new event for it, establishing a happens-before
+    /// edge from whoever released the clock to this point in `thread`'s
+    /// execution.
+    fn acquire_clock(&mut self, clock: &VClock, thread: ThreadId) {
+        let this = self.eval_context_mut();
+        let thread_clock = this.machine.data_race.clock_mut(thread);
+        thread_clock.join(clock);
+        thread_clock.increment(thread);
+    }
+}
@@ -2,9 +2,11 @@ use std::collections::{hash_map::Entry, HashMap, VecDeque};
 use std::convert::TryFrom;
 use std::num::NonZeroU32;
 use std::ops::Not;
+use std::time::Duration;
 
 use rustc_index::vec::{Idx, IndexVec};
 
+use crate::data_race::{EvalContextExt as _, VClock};
 use crate::*;
 
 /// We cannot use the `newtype_index!` macro because we have to use 0 as a
@@ -59,6 +61,9 @@ struct Mutex {
     lock_count: usize,
     /// The queue of threads waiting for this mutex.
     queue: VecDeque<ThreadId>,
+    /// The vector clock of the last thread to release this mutex, acquired
+    /// by the next thread to lock it.
+    data_race: VClock,
 }
 
 declare_id!(RwLockId);
@@ -75,6 +80,13 @@ struct RwLock {
     writer_queue: VecDeque<ThreadId>,
     /// The queue of reader threads waiting for this lock.
     reader_queue: VecDeque<ThreadId>,
+    /// The vector clock of the last writer to release this lock, acquired
+    /// by the next writer or reader to take the lock.
+    data_race: VClock,
+    /// The join of the vector clocks of all readers that have released this
+    /// lock since it was last write-locked, acquired by the next writer.
+    /// Readers do not synchronize with each other, only with the writer.
+    data_race_reader: VClock,
 }
 
 declare_id!(CondvarId);
@@ -94,12 +106,38 @@ struct Condvar {
     waiters: VecDeque<CondvarWaiter>,
 }
 
+/// A thread waiting on a futex.
+#[derive(Debug)]
+struct FutexWaiter {
+    /// The thread that is waiting on this futex.
+    thread: ThreadId,
+    /// The bitset that the waiter is waiting for; see `FUTEX_WAIT_BITSET`.
+    bitset: u32,
+}
+
+/// The futex state.
+#[derive(Default, Debug)]
+struct Futex {
+    waiters: VecDeque<FutexWaiter>,
+}
+
 /// The state of all synchronization variables.
 #[derive(Default, Debug)]
 pub(super) struct SynchronizationState {
     mutexes: IndexVec<MutexId, Mutex>,
     rwlocks: IndexVec<RwLockId, RwLock>,
     condvars: IndexVec<CondvarId, Condvar>,
+    /// Futexes, keyed by the address of the futex word.
+    futexes: HashMap<u64, Futex>,
+    /// The futex address and epoch each thread is currently enqueued on, if
+    /// any. `futex_requeue` updates the address so a pending timeout (which
+    /// only knows the thread, not which queue it has since been moved to)
+    /// can still find and cancel the right waiter. The epoch distinguishes
+    /// a thread's successive waits, so a timeout belonging to an earlier,
+    /// already-resolved wait can't be mistaken for the thread's current one.
+    futex_waiters: HashMap<ThreadId, (u64, u64)>,
+    /// The next epoch to hand out to a thread starting a new futex wait.
+    next_futex_epoch: u64,
 }
 
 // Public interface to synchronization primitives. Please note that in most
@@ -143,6 +181,10 @@ pub trait EvalContextExt<'mir, 'tcx: 'mir>: crate::MiriEvalContextExt<'mir, 'tcx
             mutex.owner = Some(thread);
         }
         mutex.lock_count = mutex.lock_count.checked_add(1).unwrap();
+        // Acquire: synchronize with the release performed by the last thread
+        // to fully unlock this mutex.
+        let mutex_clock = mutex.data_race.clone();
+        this.acquire_clock(&mutex_clock, thread);
     }
 
     /// Try unlocking by decreasing the lock count and returning the old owner
@@ -158,6 +200,9 @@ pub trait EvalContextExt<'mir, 'tcx: 'mir>: crate::MiriEvalContextExt<'mir, 'tcx
         expected_owner: ThreadId,
     ) -> InterpResult<'tcx, Option<usize>> {
         let this = self.eval_context_mut();
+        // Release: snapshot the unlocking thread's clock before taking a
+        // mutable borrow of the mutex below.
+        let released = this.release_clock();
         let mutex = &mut this.machine.threads.sync.mutexes[id];
         if let Some(current_owner) = mutex.owner {
             // Mutex is locked.
@@ -171,6 +216,7 @@ pub trait EvalContextExt<'mir, 'tcx: 'mir>: crate::MiriEvalContextExt<'mir, 'tcx
                 .expect("invariant violation: lock_count == 0 iff the thread is unlocked");
             if mutex.lock_count == 0 {
                 mutex.owner = None;
+                mutex.data_race = released;
                 // The mutex is completely unlocked. Try transfering ownership
                 // to another thread.
                 if let Some(new_owner) = this.mutex_dequeue(id) {
@@ -229,18 +275,28 @@ pub trait EvalContextExt<'mir, 'tcx: 'mir>: crate::MiriEvalContextExt<'mir, 'tcx
         assert!(!this.rwlock_is_write_locked(id), "the lock is write locked");
         let count = this.machine.threads.sync.rwlocks[id].readers.entry(reader).or_insert(0);
         *count = count.checked_add(1).expect("the reader counter overflowed");
+        // Acquire: readers synchronize with the last writer to release this
+        // lock, but not with each other.
+        let writer_clock = this.machine.threads.sync.rwlocks[id].data_race.clone();
+        this.acquire_clock(&writer_clock, reader);
     }
 
     /// Try read-unlock the lock for `reader`. Returns `true` if succeeded,
     /// `false` if this `reader` did not hold the lock.
     fn rwlock_reader_unlock(&mut self, id: RwLockId, reader: ThreadId) -> bool {
         let this = self.eval_context_mut();
+        // Release: snapshot the unlocking reader's clock before taking a
+        // mutable borrow of the lock below.
+        let released = this.release_clock();
         match this.machine.threads.sync.rwlocks[id].readers.entry(reader) {
             Entry::Occupied(mut entry) => {
                 let count = entry.get_mut();
                 *count -= 1;
                 if *count == 0 {
                     entry.remove();
+                    // Accumulate this reader's clock for the next writer to
+                    // acquire; readers do not synchronize with each other.
+                    this.machine.threads.sync.rwlocks[id].data_race_reader.join(&released);
                 }
                 true
             }
@@ -273,14 +329,27 @@ pub trait EvalContextExt<'mir, 'tcx: 'mir>: crate::MiriEvalContextExt<'mir, 'tcx
     fn rwlock_writer_lock(&mut self, id: RwLockId, writer: ThreadId) {
         let this = self.eval_context_mut();
         assert!(!this.rwlock_is_locked(id), "the lock is already locked");
-        this.machine.threads.sync.rwlocks[id].writer = Some(writer);
+        let rwlock = &mut this.machine.threads.sync.rwlocks[id];
+        rwlock.writer = Some(writer);
+        // Acquire: synchronize with the last writer and with every reader
+        // that has released the lock since, then reset the reader
+        // accumulator for the next round of readers.
+        let mut clock = rwlock.data_race.clone();
+        clock.join(&std::mem::take(&mut rwlock.data_race_reader));
+        this.acquire_clock(&clock, writer);
     }
 
     #[inline]
     /// Try to unlock by removing the writer.
     fn rwlock_writer_unlock(&mut self, id: RwLockId) -> Option<ThreadId> {
         let this = self.eval_context_mut();
-        this.machine.threads.sync.rwlocks[id].writer.take()
+        let writer = this.machine.threads.sync.rwlocks[id].writer.take();
+        if writer.is_some() {
+            // Release: publish the unlocking writer's clock to the next
+            // owner(s) of the lock.
+            this.machine.threads.sync.rwlocks[id].data_race = this.release_clock();
+        }
+        writer
     }
 
     #[inline]
@@ -329,10 +398,15 @@ pub trait EvalContextExt<'mir, 'tcx: 'mir>: crate::MiriEvalContextExt<'mir, 'tcx
     /// variable.
     fn condvar_signal(&mut self, id: CondvarId) -> Option<(ThreadId, MutexId)> {
         let this = self.eval_context_mut();
-        this.machine.threads.sync.condvars[id]
-            .waiters
-            .pop_front()
-            .map(|waiter| (waiter.thread, waiter.mutex))
+        // Release: snapshot the signaling thread's clock before dequeuing a
+        // waiter to acquire it.
+        let released = this.release_clock();
+        this.machine.threads.sync.condvars[id].waiters.pop_front().map(|waiter| {
+            // The woken thread synchronizes with the signal directly, not
+            // (only) through reacquiring the mutex.
+            this.acquire_clock(&released, waiter.thread);
+            (waiter.thread, waiter.mutex)
+        })
     }
 
     #[inline]
@@ -341,4 +415,126 @@ pub trait EvalContextExt<'mir, 'tcx: 'mir>: crate::MiriEvalContextExt<'mir, 'tcx
         let this = self.eval_context_mut();
         this.machine.threads.sync.condvars[id].waiters.retain(|waiter| waiter.thread != thread);
     }
+
+    /// Block the active thread on the futex at `addr` until it is woken up
+    /// by a `futex_wake` matching `bitset` (use `u32::MAX` for a plain,
+    /// non-bitset wait), or, if `timeout` is given, until the timeout
+    /// elapses, whichever comes first.
+    fn futex_wait(&mut self, addr: u64, bitset: u32, timeout: Option<Duration>) -> InterpResult<'tcx> {
+        let this = self.eval_context_mut();
+        let thread = this.active_thread();
+        let futex = this.machine.threads.sync.futexes.entry(addr).or_default();
+        assert!(
+            futex.waiters.iter().all(|waiter| waiter.thread != thread),
+            "thread is already waiting on this futex"
+        );
+        futex.waiters.push_back(FutexWaiter { thread, bitset });
+        // Tag this wait with a fresh epoch so a timeout belonging to an
+        // earlier wait of the same thread can never be confused with this
+        // one (e.g. if `thread` is woken, retries, and starts a new wait
+        // before its old timeout fires).
+        let epoch = this.machine.threads.sync.next_futex_epoch;
+        this.machine.threads.sync.next_futex_epoch += 1;
+        this.machine.threads.sync.futex_waiters.insert(thread, (addr, epoch));
+        this.block_thread(thread)?;
+        if let Some(timeout) = timeout {
+            // If nobody wakes us up before the deadline, stop waiting and
+            // get rescheduled instead of blocking forever. By the time this
+            // fires, `thread` may already have been woken (by `futex_wake`),
+            // moved to a different queue (by `futex_requeue`), or have
+            // started an unrelated later wait, so only unblock it if we
+            // actually find and remove the waiter for this exact `epoch`.
+            this.register_timeout_callback(
+                thread,
+                timeout,
+                Box::new(move |this| {
+                    if this.futex_remove_waiter(thread, epoch) {
+                        this.unblock_thread(thread)?;
+                    }
+                    Ok(())
+                }),
+            );
+        }
+        Ok(())
+    }
+
+    /// Remove `thread`'s pending futex wait, wherever it is currently
+    /// queued (a `futex_requeue` may have moved it since it started
+    /// waiting), but only if it is still the wait tagged with `epoch` —
+    /// not a later, unrelated wait that happens to reuse the same thread.
+    /// Returns whether a waiter was actually found and removed.
+    fn futex_remove_waiter(&mut self, thread: ThreadId, epoch: u64) -> bool {
+        let this = self.eval_context_mut();
+        let addr = match this.machine.threads.sync.futex_waiters.get(&thread) {
+            Some(&(addr, current_epoch)) if current_epoch == epoch => addr,
+            _ => return false,
+        };
+        this.machine.threads.sync.futex_waiters.remove(&thread);
+        match this.machine.threads.sync.futexes.get_mut(&addr) {
+            Some(futex) => {
+                let len_before = futex.waiters.len();
+                futex.waiters.retain(|waiter| waiter.thread != thread);
+                futex.waiters.len() != len_before
+            }
+            None => false,
+        }
+    }
+
+    /// Wake up to `count` threads waiting on the futex at `addr` whose
+    /// bitset intersects `bitset`, returning how many were actually woken.
+    fn futex_wake(&mut self, addr: u64, bitset: u32, count: u32) -> InterpResult<'tcx, u32> {
+        let this = self.eval_context_mut();
+        // Release: snapshot the waking thread's clock to hand to whoever we wake up.
+        let released = this.release_clock();
+        let futex = match this.machine.threads.sync.futexes.get_mut(&addr) {
+            Some(futex) => futex,
+            None => return Ok(0),
+        };
+        let mut woken = Vec::new();
+        let mut remaining = VecDeque::new();
+        for waiter in futex.waiters.drain(..) {
+            if woken.len() < count as usize && waiter.bitset & bitset != 0 {
+                woken.push(waiter.thread);
+            } else {
+                remaining.push_back(waiter);
+            }
+        }
+        futex.waiters = remaining;
+        for thread in &woken {
+            this.machine.threads.sync.futex_waiters.remove(thread);
+            // Acquire: the woken thread synchronizes with the wake-up.
+            this.acquire_clock(&released, *thread);
+            this.unblock_thread(*thread)?;
+        }
+        Ok(woken.len() as u32)
+    }
+
+    /// Move up to `count` waiters from the futex at `addr` to the futex at
+    /// `new_addr`, without waking them up. Returns how many were moved.
+    fn futex_requeue(&mut self, addr: u64, new_addr: u64, count: u32) -> InterpResult<'tcx, u32> {
+        let this = self.eval_context_mut();
+        let mut moved = Vec::new();
+        if let Some(futex) = this.machine.threads.sync.futexes.get_mut(&addr) {
+            for _ in 0..count {
+                match futex.waiters.pop_front() {
+                    Some(waiter) => moved.push(waiter),
+                    None => break,
+                }
+            }
+        }
+        let n = moved.len() as u32;
+        if !moved.is_empty() {
+            // Re-point each moved waiter's pending timeout (if any) at
+            // `new_addr`, so it can still find and cancel the waiter after
+            // the requeue. The epoch is left untouched: this is still the
+            // same wait, just relocated to a different queue.
+            for waiter in &moved {
+                if let Some(entry) = this.machine.threads.sync.futex_waiters.get_mut(&waiter.thread) {
+                    entry.0 = new_addr;
+                }
+            }
+            this.machine.threads.sync.futexes.entry(new_addr).or_default().waiters.extend(moved);
+        }
+        Ok(n)
+    }
 }